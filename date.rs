@@ -1,6 +1,7 @@
 #![recursion_limit="128"]
 
 extern crate chrono;
+extern crate chrono_tz;
 #[macro_use]
 extern crate clap;
 extern crate errno;
@@ -18,17 +19,18 @@ extern crate winapi;
 /*
  * TODO
  * - make print and set argument groups mutually exclusive
- * - implement "(nearly) arbitrary text-to-datetime" parser
- * - print timezone abbrevations instead of UTC offsets
  */
 
 
+use std::env;
 use std::fs::{self, File};
 use std::io::{self, Write, BufReader, BufRead};
 use std::path::PathBuf;
 use std::time;
 
-use chrono::{DateTime, Offset, FixedOffset, Local, UTC, Datelike, Timelike, TimeZone};
+use chrono::{DateTime, Duration, Offset, FixedOffset, Local, NaiveDate, NaiveDateTime, UTC, Datelike,
+             Timelike, TimeZone};
+use chrono_tz::Tz;
 use clap::{App, Arg, ArgGroup};
 use errno::errno;
 use nom::digit;
@@ -103,6 +105,11 @@ mod errors {
                 description("cannot parse arbitrary datetime")
                 display("cannot parse arbitrary datetime: '{}'", s)
             }
+
+            UnknownTimeZone(name: String) {
+                description("unknown time zone")
+                display("unknown time zone: '{}'", name)
+            }
         }
     }
 
@@ -114,8 +121,227 @@ mod errors {
 }
 
 
+// A small grammar for GNU-style relative date expressions, e.g.
+// "3 weeks ago", "next monday", "tomorrow", "last month".
+mod human {
+    use std::io::{self, Write};
+
+    use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, TimeZone};
+    use nom::digit;
+
+    use errors;
+
+    named!(signed_amount<&str, i64>, do_parse!(
+        sign: opt!(alt!(char!('+') | char!('-'))) >>
+        digits: digit >>
+
+        (match sign {
+            Some('-') => -(digits.parse::<i64>().expect("digit parser guarantees an integer")),
+            _ => digits.parse::<i64>().expect("digit parser guarantees an integer"),
+        })
+    ));
+
+    fn months_per_unit(unit: &str) -> Option<i32> {
+        match unit {
+            "month" | "months" => Some(1),
+            "year" | "years" => Some(12),
+            _ => None,
+        }
+    }
+
+    fn duration_per_unit(unit: &str) -> Option<Duration> {
+        match unit {
+            "sec" | "secs" | "second" | "seconds" => Some(Duration::seconds(1)),
+            "min" | "mins" | "minute" | "minutes" => Some(Duration::minutes(1)),
+            "hour" | "hours" => Some(Duration::hours(1)),
+            "day" | "days" => Some(Duration::days(1)),
+            "week" | "weeks" => Some(Duration::weeks(1)),
+            "fortnight" | "fortnights" => Some(Duration::days(14)),
+            _ => None,
+        }
+    }
+
+    fn weekday_index(name: &str) -> Option<i64> {
+        match name {
+            "sunday" => Some(0),
+            "monday" => Some(1),
+            "tuesday" => Some(2),
+            "wednesday" => Some(3),
+            "thursday" => Some(4),
+            "friday" => Some(5),
+            "saturday" => Some(6),
+            _ => None,
+        }
+    }
+
+    // `sign` is +1 for "next"/bare weekday names, -1 for "last", 0 for "this".
+    fn days_until_weekday(current: i64, target: i64, sign: i32) -> i64 {
+        if sign < 0 {
+            let delta = (current - target).rem_euclid(7);
+            if delta == 0 { -7 } else { -delta }
+        } else if sign > 0 {
+            let delta = (target - current).rem_euclid(7);
+            if delta == 0 { 7 } else { delta }
+        } else {
+            (target - current).rem_euclid(7)
+        }
+    }
+
+    fn days_in_month(year: i32, month: u32) -> u32 {
+        match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+            2 => 28,
+            _ => unreachable!("month is always in 1..=12"),
+        }
+    }
+
+    // Shift `base` by whole calendar months, clamping the day-of-month instead
+    // of overflowing into the following month (e.g. Jan 31 + 1 month = Feb 28).
+    fn shift_months(base: DateTime<FixedOffset>, months: i32, debug: bool) -> errors::Result<DateTime<FixedOffset>> {
+        if months == 0 {
+            return Ok(base);
+        }
+
+        let total = base.month0() as i32 + months;
+        let year = base.year() + total.div_euclid(12);
+        let month = (total.rem_euclid(12) + 1) as u32;
+
+        let day = base.day().min(days_in_month(year, month));
+
+        if debug && day != base.day() {
+            writeln!(io::stderr(), "date: shifting by {} months clamped day-of-month {} -> {}",
+                     months, base.day(), day).expect("couldn't write to stderr");
+        }
+
+        // Build the (year, month, day) triple in one step rather than chaining
+        // with_year/with_month/with_day: each of those validates the new field
+        // against whichever *other* two fields haven't been updated yet, so
+        // any chained order can reject an intermediate date (e.g. Feb 29 on a
+        // still-old, non-leap year) even though the final date is valid.
+        let invalid = || errors::ErrorKind::ArbitraryDateTimeParse(
+            format!("{:04}-{:02}-{:02}", year, month, day)).into();
+
+        let naive = NaiveDate::from_ymd_opt(year, month, day)
+            .ok_or_else(invalid)?
+            .and_time(base.time());
+
+        base.timezone().from_local_datetime(&naive).single().ok_or_else(invalid)
+    }
+
+    /// Parse a relative date expression and apply it to `base`.
+    ///
+    /// Accepts whitespace-separated items: `now`/`today`, `yesterday`,
+    /// `tomorrow`; signed count+unit pairs (`3 weeks`, `-2 days`) where the
+    /// count may also be spelled `next`/`last`/`this`; weekday names, which
+    /// roll forward to their next occurrence (or combine with `next`/`last`);
+    /// and a trailing `ago`, which negates everything accumulated so far.
+    pub fn parse(input: &str, base: DateTime<FixedOffset>, debug: bool) -> errors::Result<DateTime<FixedOffset>> {
+        let tokens: Vec<String> = input.split_whitespace().map(|s| s.to_lowercase()).collect();
+        if tokens.is_empty() {
+            return Err(invalid(input));
+        }
+
+        let mut months = 0i32;
+        let mut duration = Duration::zero();
+        let mut negate = false;
+        let mut matched_any = false;
+
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = tokens[i].as_str();
+            i += 1;
+
+            if debug {
+                writeln!(io::stderr(), "date: relative expression '{}' consuming item '{}'",
+                         input, token).expect("couldn't write to stderr");
+            }
+
+            match token {
+                "ago" => { negate = true; matched_any = true; continue; },
+                "now" | "today" => { matched_any = true; continue; },
+                "yesterday" => { duration = duration + Duration::days(-1); matched_any = true; continue; },
+                "tomorrow" => { duration = duration + Duration::days(1); matched_any = true; continue; },
+                _ => {},
+            }
+
+            let sign = match token {
+                "next" => Some(1),
+                "last" => Some(-1),
+                "this" => Some(0),
+                _ => None,
+            };
+
+            if let Some(sign) = sign {
+                let next = tokens.get(i).ok_or_else(|| invalid(input))?.as_str();
+                i += 1;
+
+                if let Some(target) = weekday_index(next) {
+                    let current = (base + duration).weekday().num_days_from_sunday() as i64;
+                    duration = duration + Duration::days(days_until_weekday(current, target, sign));
+                } else if let Some(per_unit) = months_per_unit(next) {
+                    months += sign * per_unit;
+                } else if let Some(per_unit) = duration_per_unit(next) {
+                    duration = duration + per_unit * (sign as i32);
+                } else {
+                    return Err(invalid(input));
+                }
+
+                matched_any = true;
+                continue;
+            }
+
+            if let Some(target) = weekday_index(token) {
+                let current = (base + duration).weekday().num_days_from_sunday() as i64;
+                duration = duration + Duration::days(days_until_weekday(current, target, 1));
+                matched_any = true;
+                continue;
+            }
+
+            let count = signed_amount(token).to_full_result().map_err(|_| invalid(input))?;
+            let unit = tokens.get(i).ok_or_else(|| invalid(input))?.as_str();
+            i += 1;
+
+            if let Some(per_unit) = months_per_unit(unit) {
+                months += (count as i32) * per_unit;
+            } else if let Some(per_unit) = duration_per_unit(unit) {
+                duration = duration + per_unit * (count as i32);
+            } else {
+                return Err(invalid(input));
+            }
+            matched_any = true;
+        }
+
+        if !matched_any {
+            return Err(invalid(input));
+        }
+
+        if negate {
+            months = -months;
+            duration = -duration;
+        }
+
+        let result = shift_months(base, months, debug)? + duration;
+
+        if debug {
+            writeln!(io::stderr(), "date: relative expression '{}' resolved to {}",
+                     input, result.to_rfc3339()).expect("couldn't write to stderr");
+        }
+
+        Ok(result)
+    }
+
+    fn invalid(input: &str) -> errors::Error {
+        errors::ErrorKind::ArbitraryDateTimeParse(input.to_owned()).into()
+    }
+}
+
+
 struct Settings {
     utc: bool,
+    debug: bool,
+    timezone: Option<Tz>,
     date_source: DateSource,
     format: Format,
     set_to: Option<DateTime<FixedOffset>>,
@@ -124,7 +350,9 @@ struct Settings {
 enum DateSource {
     Now,
     Custom(String),
+    Human(String),
     File(PathBuf),
+    Stdin,
     Reference(PathBuf),
 }
 
@@ -195,18 +423,32 @@ fn uumain_impl(args: Vec<String>) -> errors::Result<()> {
     } else {
         let format_string = make_format_string(&settings.format);
         let print_date = |date: DateTime<FixedOffset>| {
-            println!("{}", date.format(format_string));
+            match settings.timezone {
+                Some(tz) => println!("{}", date.with_timezone(&tz).format(format_string)),
+                None => println!("{}", date.format(format_string)),
+            }
         };
 
         match settings.date_source {
             DateSource::Custom(ref input) => {
-                let date = input.parse()?;
+                let date = parse_date_time(input, &settings.timezone, settings.utc, settings.debug)?;
+                print_date(date)
+            },
+            DateSource::Human(ref input) => {
+                let date = human::parse(input, get_now(&settings.timezone, settings.utc), settings.debug)?;
                 print_date(date)
             },
             DateSource::File(ref path) => {
                 let file = File::open(path)?;
                 for line in BufReader::new(file).lines() {
-                    let date = line?.parse()?;
+                    let date = parse_date_time(&line?, &settings.timezone, settings.utc, settings.debug)?;
+                    print_date(date);
+                }
+            },
+            DateSource::Stdin => {
+                let stdin = io::stdin();
+                for line in stdin.lock().lines() {
+                    let date = parse_date_time(&line?, &settings.timezone, settings.utc, settings.debug)?;
                     print_date(date);
                 }
             },
@@ -217,7 +459,7 @@ fn uumain_impl(args: Vec<String>) -> errors::Result<()> {
                 print_date(date.with_timezone(&date.offset().fix()))
             },
             DateSource::Now => {
-                print_date(get_now(settings.utc))
+                print_date(get_now(&settings.timezone, settings.utc))
             },
         };
     }
@@ -236,7 +478,8 @@ fn parse_cli(args: Vec<String>) -> errors::Result<Settings> {
                  .long("date")
                  .value_name("STRING"))
         .arg(Arg::with_name("file")
-                 .help("like --date once for each line of DATEFILE")
+                 .help("like --date once for each line of DATEFILE, {n}\
+                        or standard input if DATEFILE is '-'")
                  .short("f")
                  .long("file")
                  .value_name("DATEFILE"))
@@ -278,6 +521,16 @@ fn parse_cli(args: Vec<String>) -> errors::Result<Settings> {
                  .short("u")
                  .long("utc")
                  .long("universal"))
+        .arg(Arg::with_name("debug")
+                 .help("annotate the parsed date, and warn about questionable {n}\
+                        use of date(1)")
+                 .long("debug"))
+        .arg(Arg::with_name("timezone")
+                 .help("display time in TIMEZONE, an IANA time zone name {n}\
+                        such as 'America/New_York' (overrides the TZ {n}\
+                        environment variable)")
+                 .long("timezone")
+                 .value_name("TIMEZONE"))
         .arg(Arg::with_name("format")
                  .value_name("+FORMAT")
                  .validator(|fmt| match fmt.starts_with('+') {
@@ -296,6 +549,18 @@ fn parse_cli(args: Vec<String>) -> errors::Result<Settings> {
         .get_matches_from(args);
 
     let utc = matches.is_present("utc");
+    let debug = matches.is_present("debug");
+
+    let timezone = if let Some(tz) = matches.value_of("timezone") {
+        Some(tz.parse::<Tz>().map_err(|_| errors::ErrorKind::UnknownTimeZone(tz.to_owned()))?)
+    } else if utc {
+        // --utc takes precedence over the TZ environment variable.
+        None
+    } else {
+        // An unparseable TZ is ignored rather than rejected, matching how a
+        // shell environment's stray/legacy TZ value is usually tolerated.
+        env::var("TZ").ok().and_then(|tz| tz.parse::<Tz>().ok())
+    };
 
     let format = if let Some(fmt) = matches.value_of("format") {
         let fmt = fmt[1..].into();
@@ -313,9 +578,20 @@ fn parse_cli(args: Vec<String>) -> errors::Result<Settings> {
     };
 
     let date_source = if let Some(date) = matches.value_of("date") {
-        DateSource::Custom(date.into())
+        // Probe only: whether this looks like an RFC/ISO date decides Custom
+        // vs. Human here, but the actual parse (and its --debug annotations)
+        // happens once, for real, in uumain_impl.
+        if parse_date_time(date, &timezone, utc, false).is_ok() {
+            DateSource::Custom(date.into())
+        } else {
+            DateSource::Human(date.into())
+        }
     } else if let Some(file) = matches.value_of("file") {
-        DateSource::File(file.into())
+        if file == "-" {
+            DateSource::Stdin
+        } else {
+            DateSource::File(file.into())
+        }
     } else if let Some(reference) = matches.value_of("reference") {
         DateSource::Reference(reference.into())
     } else {
@@ -323,22 +599,24 @@ fn parse_cli(args: Vec<String>) -> errors::Result<Settings> {
     };
 
     let set_to = if let Some(time) = matches.value_of("positional set") {
-        Some(parse_custom_date_time(time, utc)?)
+        Some(parse_custom_date_time(time, &timezone, utc, debug)?)
     } else if let Some(time) = matches.value_of("set") {
-        Some(parse_date_time(time)?)
+        Some(parse_date_time(time, &timezone, utc, debug)?)
     } else {
         None
     };
 
     Ok(Settings {
         utc: utc,
+        debug: debug,
+        timezone: timezone,
         format: format,
         date_source: date_source,
         set_to: set_to,
     })
 }
 
-fn parse_custom_date_time(time: &str, utc: bool) -> errors::Result<DateTime<FixedOffset>> {
+fn parse_custom_date_time(time: &str, timezone: &Option<Tz>, utc: bool, debug: bool) -> errors::Result<DateTime<FixedOffset>> {
     named!(two_digits<&str, u32>, do_parse!(
         first: digit >>
         second: digit >>
@@ -355,13 +633,13 @@ fn parse_custom_date_time(time: &str, utc: bool) -> errors::Result<DateTime<Fixe
         before_dot1: opt!(two_digits) >>
         before_dot2: opt!(two_digits) >>
         second: opt!(do_parse!(
-            char!('.') >> 
+            char!('.') >>
             digits: two_digits >>
             (digits)
         )) >>
 
         ({
-            let date_time = get_now(utc);
+            let date_time = get_now(timezone, utc);
 
             let year = before_dot1.map_or(date_time.year(), |yy| date_time.year() / 100 + yy as i32);
             let year = before_dot2.map_or(year, |yy| year / 10000 + yy as i32 * 100 + year % 100);
@@ -377,20 +655,95 @@ fn parse_custom_date_time(time: &str, utc: bool) -> errors::Result<DateTime<Fixe
         })
     );
 
-    Ok(res.to_full_result()?)
+    let parsed = res.to_full_result()?;
+
+    if debug {
+        writeln!(io::stderr(), "date: parsed '{}' as MMDDhhmm[[CC]YY][.ss] -> {}",
+                 time, parsed.to_rfc3339()).expect("couldn't write to stderr");
+    }
+
+    Ok(parsed)
 }
 
-fn parse_date_time(time: &str) -> errors::Result<DateTime<FixedOffset>> {
-    // TODO: Implement more conversion formats (and reorder to match that of GNU counterpart?)
-    let parse_functions = [
-        DateTime::<FixedOffset>::parse_from_rfc2822,
-        DateTime::<FixedOffset>::parse_from_rfc3339];
+fn parse_date_time(time: &str, timezone: &Option<Tz>, utc: bool, debug: bool) -> errors::Result<DateTime<FixedOffset>> {
+    // Tried in priority order: strict RFC 2822/3339 first, then the
+    // space-separated RFC 3339 variant our own --rfc-3339 output produces,
+    // then looser ISO-ish forms down to a bare date. Naive forms carry no
+    // offset of their own, so they're anchored to the current/--utc/--timezone
+    // zone. The default output format and the --iso-8601=hours, =minutes and
+    // =ns outputs match none of these patterns.
+    macro_rules! try_format {
+        ($name:expr, $parse:expr) => {
+            match $parse(time) {
+                Ok(date) => {
+                    if debug {
+                        writeln!(io::stderr(), "date: '{}' parsed as {} -> {}",
+                                 time, $name, date.to_rfc3339()).expect("couldn't write to stderr");
+                    }
+                    return Ok(date);
+                },
+                Err(_) => {
+                    if debug {
+                        writeln!(io::stderr(), "date: '{}' did not match {}",
+                                 time, $name).expect("couldn't write to stderr");
+                    }
+                },
+            }
+        };
+    }
 
-    for parse in parse_functions.iter() {
-        match parse(time) {
-            Ok(date) => return Ok(date),
-            Err(_)   => continue,
-        }
+    try_format!("rfc2822", DateTime::<FixedOffset>::parse_from_rfc2822);
+    try_format!("rfc3339", DateTime::<FixedOffset>::parse_from_rfc3339);
+
+    for &(name, fmt) in &[
+        ("iso8601 space", "%Y-%m-%d %H:%M:%S%:z"),
+        ("iso8601 space, fractional seconds", "%Y-%m-%d %H:%M:%S%.f%:z"),
+        ("iso8601 T, fractional seconds", "%Y-%m-%dT%H:%M:%S%.f%:z"),
+        ("rfc2822 without weekday", "%d %b %Y %H:%M:%S %z"),
+    ] {
+        try_format!(name, |s| DateTime::<FixedOffset>::parse_from_str(s, fmt));
+    }
+
+    macro_rules! try_naive {
+        ($name:expr, $fmt:expr) => {
+            match NaiveDateTime::parse_from_str(time, $fmt) {
+                Ok(naive) => {
+                    let date = localize_naive(naive, timezone, utc)?;
+                    if debug {
+                        writeln!(io::stderr(), "date: '{}' parsed as {} (in current zone) -> {}",
+                                 time, $name, date.to_rfc3339()).expect("couldn't write to stderr");
+                    }
+                    return Ok(date);
+                },
+                Err(_) => {
+                    if debug {
+                        writeln!(io::stderr(), "date: '{}' did not match {}",
+                                 time, $name).expect("couldn't write to stderr");
+                    }
+                },
+            }
+        };
+    }
+
+    try_naive!("naive datetime, space", "%Y-%m-%d %H:%M:%S");
+    try_naive!("naive datetime, T", "%Y-%m-%dT%H:%M:%S");
+
+    match NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        Ok(naive_date) => {
+            let naive = naive_date.and_hms(0, 0, 0);
+            let date = localize_naive(naive, timezone, utc)?;
+            if debug {
+                writeln!(io::stderr(), "date: '{}' parsed as bare date (in current zone) -> {}",
+                         time, date.to_rfc3339()).expect("couldn't write to stderr");
+            }
+            return Ok(date);
+        },
+        Err(_) => {
+            if debug {
+                writeln!(io::stderr(), "date: '{}' did not match bare date", time)
+                    .expect("couldn't write to stderr");
+            }
+        },
     }
 
     Err(errors::ErrorKind::ArbitraryDateTimeParse(time.to_owned()).into())
@@ -451,8 +804,32 @@ fn set_time(date_time: &DateTime<FixedOffset>) -> errors::Result<()> {
 }
 
 
-fn get_now(utc: bool) -> DateTime<FixedOffset> {
-    if utc {
+// Resolve a naive (offset-less) datetime against the current/--utc/--timezone
+// zone, using the offset that actually applies *to that datetime* (so DST
+// transitions are handled correctly even when the parsed date is far from
+// "now").
+fn localize_naive(naive: NaiveDateTime, timezone: &Option<Tz>, utc: bool) -> errors::Result<DateTime<FixedOffset>> {
+    let ambiguous = || errors::ErrorKind::ArbitraryDateTimeParse(
+        naive.format("%Y-%m-%d %H:%M:%S").to_string()).into();
+
+    if let Some(tz) = *timezone {
+        let date = tz.from_local_datetime(&naive).single().ok_or_else(ambiguous)?;
+        Ok(date.with_timezone(&date.offset().fix()))
+    } else if utc {
+        let date = UTC.from_local_datetime(&naive).single().ok_or_else(ambiguous)?;
+        Ok(date.with_timezone(&date.offset().fix()))
+    } else {
+        let date = Local.from_local_datetime(&naive).single().ok_or_else(ambiguous)?;
+        Ok(date.with_timezone(date.offset()))
+    }
+}
+
+
+fn get_now(timezone: &Option<Tz>, utc: bool) -> DateTime<FixedOffset> {
+    if let Some(tz) = *timezone {
+        let now = UTC::now().with_timezone(&tz);
+        now.with_timezone(&now.offset().fix())
+    } else if utc {
         let now = UTC::now();
         now.with_timezone(&now.offset().fix())
     } else {